@@ -1,30 +1,589 @@
 #![cfg(feature = "scripting")]
 
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
 #[cfg(target_arch = "wasm32-unknown-unknown")]
 use trice::Instant;
 #[cfg(not(target_arch = "wasm32-unknown-unknown"))]
 use std::time::Instant;
 
-/// A 'static view into the cancellation status of a Context.
+/// Default number of [`Cancellation::should_yield`] calls between
+/// cooperative yields.
+const DEFAULT_YIELD_BUDGET: usize = 128;
+
+/// Why a [`Cancellation`] tripped.
+///
+/// `is_done()` used to collapse timeouts, parent-cancellation and explicit
+/// kills into a single bool; this lets callers tell them apart and surface a
+/// precise error instead of a generic "query cancelled".
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum CancellationReason {
+	/// The context's deadline elapsed.
+	Timeout,
+	/// The query was explicitly killed, e.g. via `KILL`.
+	Killed,
+	/// An ancestor context was cancelled and it propagated down.
+	ParentCancelled,
+	/// The server's memory pressure sampler tripped.
+	MemoryPressure,
+	/// The datastore's write revision advanced past the snapshot this
+	/// read-only context started at; see [`Cancellation::cancel_on_write`].
+	Stale,
+}
+
+/// A datastore's global write-generation counter.
+///
+/// The datastore holds one of these and calls [`WriteRevision::bump`] once
+/// per committed write; read-only contexts opt in via
+/// [`Cancellation::cancel_on_write`], which snapshots the current value and
+/// registers to be woken the moment it advances past that snapshot.
 #[derive(Clone, Debug, Default)]
+pub struct WriteRevision {
+	counter: Arc<AtomicU64>,
+	waiters: Arc<Mutex<Vec<Weak<tokio::sync::Notify>>>>,
+}
+
+impl WriteRevision {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// The current revision, as last observed — a single relaxed atomic
+	/// load, cheap enough for [`Cancellation::reason`] to call on every
+	/// check.
+	pub fn snapshot(&self) -> u64 {
+		self.counter.load(Ordering::Relaxed)
+	}
+
+	/// Record a committed write: advance the revision and wake every
+	/// context watching it via [`Cancellation::cancel_on_write`], pruning
+	/// any whose context has since been dropped.
+	pub fn bump(&self) {
+		self.counter.fetch_add(1, Ordering::Relaxed);
+		self.waiters.lock().unwrap().retain(|notify| match notify.upgrade() {
+			Some(notify) => {
+				notify.notify_waiters();
+				true
+			}
+			None => false,
+		});
+	}
+
+	/// Register `notify` to be woken by the next [`WriteRevision::bump`].
+	fn subscribe(&self, notify: &Arc<tokio::sync::Notify>) {
+		self.waiters.lock().unwrap().push(Arc::downgrade(notify));
+	}
+}
+
+/// A 'static view into the cancellation status of a Context.
+#[derive(Clone, Debug)]
 #[non_exhaustive]
 pub struct Cancellation {
 	deadline: Option<Instant>,
-	cancellations: Vec<Arc<AtomicBool>>,
+	cancellations: Vec<(Arc<AtomicBool>, CancellationReason)>,
+	notify: Arc<tokio::sync::Notify>,
+	/// The write revision this context is watching, and the value it held
+	/// when the context was constructed, for contexts opted into
+	/// [`Cancellation::cancel_on_write`].
+	revision: Option<(WriteRevision, u64)>,
+	/// Cooperative-scheduling budget; see [`Cancellation::should_yield`].
+	yield_budget: Arc<AtomicUsize>,
 }
 
 impl Cancellation {
-	pub fn new(deadline: Option<Instant>, cancellations: Vec<Arc<AtomicBool>>) -> Cancellation {
+	pub fn new(
+		deadline: Option<Instant>,
+		cancellations: Vec<(Arc<AtomicBool>, CancellationReason)>,
+	) -> Cancellation {
 		Self {
 			deadline,
 			cancellations,
+			notify: Arc::new(tokio::sync::Notify::new()),
+			revision: None,
+			yield_budget: Arc::new(AtomicUsize::new(DEFAULT_YIELD_BUDGET)),
+		}
+	}
+
+	/// Returns `true` roughly once every `DEFAULT_YIELD_BUDGET` calls,
+	/// resetting the counter each time it does.
+	///
+	/// Long scan/iterator loops in the executor call this alongside
+	/// `is_done()`, and `yield_now().await` when it returns `true`, so a huge
+	/// sequential scan can't monopolize a runtime thread between `.await`
+	/// points and other queries on the same worker still make progress. This
+	/// is fairness, not termination — it complements, rather than replaces,
+	/// the deadline/flag cancellation above.
+	pub fn should_yield(&self) -> bool {
+		// `fetch_update` so concurrent callers (e.g. a cloned Cancellation
+		// shared across tasks) can't under- or over-count the budget, and so
+		// the call that actually exhausts it is the one that reports `true`
+		// and resets, rather than the call after.
+		self.yield_budget
+			.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |budget| {
+				Some(if budget <= 1 {
+					DEFAULT_YIELD_BUDGET
+				} else {
+					budget - 1
+				})
+			})
+			.map(|previous| previous <= 1)
+			.unwrap_or(false)
+	}
+
+	/// Opt this context into epoch-based auto-cancellation.
+	///
+	/// `revision` is the datastore's global write-generation counter,
+	/// expected to be bumped once per committed write via
+	/// [`WriteRevision::bump`]. This snapshots its current value and
+	/// registers to be woken by it; once the live counter advances past that
+	/// snapshot, `is_done()` trips with [`CancellationReason::Stale`] and any
+	/// `wait()`/`poll_done()` caller is woken directly (not just on some
+	/// unrelated later poll), so a long-running analytical read gets aborted
+	/// and can be retried against fresh data instead of returning a stale or
+	/// torn view.
+	pub fn cancel_on_write(mut self, revision: &WriteRevision) -> Self {
+		let snapshot = revision.snapshot();
+		revision.subscribe(&self.notify);
+		self.revision = Some((revision.clone(), snapshot));
+		self
+	}
+
+	/// Opt this context into cancellation under memory pressure.
+	///
+	/// Registers this context with the process-wide sampler (starting it,
+	/// with `config`, if it isn't running yet — see
+	/// [`init_memory_pressure_sampler`] to control that startup explicitly).
+	/// Once the sampler observes RSS crossing the ceiling it was started with
+	/// (`config.ceiling_bytes` only if this call started it), this
+	/// context's own flag is set and its waiters are woken directly, so
+	/// [`Cancellation::wait`]/[`Cancellation::poll_done`] callers don't have
+	/// to rely on some unrelated later `is_done()` poll to notice. Like the
+	/// other sources here, the trip is one-way for the remaining lifetime of
+	/// this context: it does not clear if RSS later drops back down.
+	pub fn cancel_on_memory_pressure(mut self, config: MemoryPressureConfig) -> Self {
+		let flag = Arc::new(AtomicBool::new(false));
+		MemoryPressureSampler::global(config).subscribe(&flag, &self.notify);
+		self.cancellations.push((flag, CancellationReason::MemoryPressure));
+		self
+	}
+
+	/// The reason this context was cancelled, if it was.
+	///
+	/// The deadline is checked first, then the write-revision snapshot; among
+	/// the flag sources, the first one observed to be tripped wins.
+	pub fn reason(&self) -> Option<CancellationReason> {
+		if self.deadline.map(|d| d <= Instant::now()).unwrap_or(false) {
+			return Some(CancellationReason::Timeout);
+		}
+		if let Some((revision, snapshot)) = &self.revision {
+			if revision.snapshot() > *snapshot {
+				return Some(CancellationReason::Stale);
+			}
 		}
+		self.cancellations
+			.iter()
+			.find(|(flag, _)| flag.load(Ordering::Relaxed))
+			.map(|(_, reason)| *reason)
 	}
 
+	/// Synchronously check whether this context has been cancelled.
+	///
+	/// This is a handful of atomic loads and a clock read, so it's cheap
+	/// enough to call from a tight loop, but [`Cancellation::wait`] should be
+	/// preferred wherever the caller can `select!` instead of polling.
 	pub fn is_done(&self) -> bool {
-		self.deadline.map(|d| d <= Instant::now()).unwrap_or(false)
-			|| self.cancellations.iter().any(|c| c.load(Ordering::Relaxed))
+		self.reason().is_some()
+	}
+
+	/// Poll, without blocking, whether this context has been cancelled,
+	/// registering the given waker to be woken on the next change.
+	pub fn poll_done(&self, cx: &mut TaskContext<'_>) -> Poll<()> {
+		if self.is_done() {
+			return Poll::Ready(());
+		}
+		// Register interest before the second check below, so that a wake
+		// racing with the check above isn't missed.
+		let notified = self.notify.notified();
+		tokio::pin!(notified);
+		if notified.as_mut().poll(cx).is_ready() || self.is_done() {
+			return Poll::Ready(());
+		}
+		Poll::Pending
+	}
+
+	/// Wait asynchronously until this context is cancelled.
+	///
+	/// Unlike looping on [`Cancellation::is_done`], this resolves as soon as
+	/// the deadline elapses or any cancellation flag is set, so query
+	/// execution can `select!` against it concurrently with its work instead
+	/// of interleaving cancellation checks into tight loops.
+	///
+	/// Whoever sets one of the `Arc<AtomicBool>` flags passed to
+	/// [`Cancellation::new`] is responsible for calling
+	/// [`Cancellation::notify`] afterwards so that waiters here are woken
+	/// promptly rather than only on the next deadline tick.
+	pub async fn wait(&self) -> CancellationReason {
+		loop {
+			let notified = self.notify.notified();
+			tokio::pin!(notified);
+			// Register for notification *before* checking `reason()` below,
+			// mirroring `poll_done`: `notify_waiters` wakes only futures that
+			// were already registered and stores no permit the way
+			// `notify_one` would, so a `notify()` racing with the check
+			// would otherwise be lost and this could block forever.
+			notified.as_mut().enable();
+
+			if let Some(reason) = self.reason() {
+				return reason;
+			}
+
+			match self.deadline {
+				#[cfg(not(target_arch = "wasm32-unknown-unknown"))]
+				Some(deadline) => {
+					let now = Instant::now();
+					if deadline <= now {
+						return CancellationReason::Timeout;
+					}
+					tokio::select! {
+						_ = tokio::time::sleep(deadline - now) => return CancellationReason::Timeout,
+						_ = notified => {}
+					}
+				}
+				// On wasm there is no timer driver to race against, so a
+				// deadline-only cancellation relies on whoever drives the
+				// event loop to call `notify` periodically.
+				_ => notified.await,
+			}
+		}
+	}
+
+	/// Wake any task currently blocked in [`Cancellation::wait`] or
+	/// [`Cancellation::poll_done`].
+	///
+	/// This must be called after flipping one of the `Arc<AtomicBool>`
+	/// sources passed to [`Cancellation::new`], or after the deadline is
+	/// known to have elapsed, so that waiters don't sit idle.
+	pub fn notify(&self) {
+		self.notify.notify_waiters();
+	}
+}
+
+impl Default for Cancellation {
+	/// An uncancellable context with no deadline or flags — deriving this
+	/// would leave `yield_budget` at `0`, tripping `should_yield()` on its
+	/// very first call instead of after `DEFAULT_YIELD_BUDGET` like
+	/// [`Cancellation::new`] gives you, so it's implemented in terms of that
+	/// instead.
+	fn default() -> Self {
+		Self::new(None, Vec::new())
+	}
+}
+
+/// Configuration for the process-wide memory-pressure sampler; see
+/// [`Cancellation::cancel_on_memory_pressure`].
+///
+/// Only the call that actually starts the sampler's background task — the
+/// first call to [`init_memory_pressure_sampler`] or
+/// [`Cancellation::cancel_on_memory_pressure`], whichever happens first — has
+/// its `config` take effect; every later caller joins that same task and
+/// silently gets the original ceiling/interval instead. Call
+/// [`init_memory_pressure_sampler`] explicitly, early in startup, if you need
+/// to be sure which config wins.
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryPressureConfig {
+	/// RSS, in bytes, above which the sampler trips subscribers' flags.
+	pub ceiling_bytes: usize,
+	/// How often the sampler re-reads the process RSS.
+	pub interval: Duration,
+}
+
+impl Default for MemoryPressureConfig {
+	fn default() -> Self {
+		Self {
+			// 4 GiB
+			ceiling_bytes: 4 * 1024 * 1024 * 1024,
+			interval: Duration::from_millis(250),
+		}
+	}
+}
+
+/// Start the process-wide memory-pressure sampler with `config`, if it isn't
+/// already running.
+///
+/// A no-op when called after the sampler has already started, from here or
+/// from [`Cancellation::cancel_on_memory_pressure`] — see
+/// [`MemoryPressureConfig`].
+pub fn init_memory_pressure_sampler(config: MemoryPressureConfig) {
+	MemoryPressureSampler::global(config);
+}
+
+type WeakSubscriber = (Weak<AtomicBool>, Weak<tokio::sync::Notify>);
+
+/// The single, process-wide background sampler backing
+/// [`Cancellation::cancel_on_memory_pressure`].
+///
+/// Subscribers are held weakly so a `Cancellation` that's never tripped
+/// doesn't leak here for the life of the process; they're pruned on the
+/// sampler's next tick once their strong references are dropped.
+struct MemoryPressureSampler {
+	subscribers: Mutex<Vec<WeakSubscriber>>,
+}
+
+static MEMORY_PRESSURE_SAMPLER: OnceLock<MemoryPressureSampler> = OnceLock::new();
+
+impl MemoryPressureSampler {
+	/// Returns the single process-wide sampler, spawning its background task
+	/// with `config` the first time this is called.
+	fn global(config: MemoryPressureConfig) -> &'static Self {
+		MEMORY_PRESSURE_SAMPLER.get_or_init(|| {
+			#[cfg(not(target_arch = "wasm32-unknown-unknown"))]
+			tokio::spawn(Self::run(config));
+			MemoryPressureSampler {
+				subscribers: Mutex::new(Vec::new()),
+			}
+		})
+	}
+
+	/// Register a subscriber's flag and notify handle, to be driven the next
+	/// time the sampler observes memory pressure.
+	fn subscribe(&self, flag: &Arc<AtomicBool>, notify: &Arc<tokio::sync::Notify>) {
+		self.subscribers.lock().unwrap().push((Arc::downgrade(flag), Arc::downgrade(notify)));
+	}
+
+	#[cfg(not(target_arch = "wasm32-unknown-unknown"))]
+	async fn run(config: MemoryPressureConfig) {
+		loop {
+			tokio::time::sleep(config.interval).await;
+			let sampler = MEMORY_PRESSURE_SAMPLER
+				.get()
+				.expect("the sampler task is only spawned after `global` has set this");
+			let mut subscribers = sampler.subscribers.lock().unwrap();
+			// Prune every tick, not only when a sample trips below — on a
+			// healthy server that never crosses the ceiling, queries would
+			// otherwise keep piling up dead entries here forever.
+			Self::prune(&mut subscribers);
+
+			let Some(rss) = current_rss() else {
+				continue;
+			};
+			if rss < config.ceiling_bytes {
+				continue;
+			}
+			Self::trip(&mut subscribers);
+		}
+	}
+
+	/// Drop subscribers whose `Cancellation` has since been dropped, without
+	/// touching any live ones.
+	fn prune(subscribers: &mut Vec<WeakSubscriber>) {
+		subscribers.retain(|(flag, notify)| flag.strong_count() > 0 && notify.strong_count() > 0);
+	}
+
+	/// Set every live subscriber's flag — one-way, never cleared back to
+	/// `false` here, matching every other cancellation source — and wake it,
+	/// pruning subscribers whose `Cancellation` has since been dropped.
+	fn trip(subscribers: &mut Vec<WeakSubscriber>) {
+		subscribers.retain(|(flag, notify)| match (flag.upgrade(), notify.upgrade()) {
+			(Some(flag), Some(notify)) => {
+				flag.store(true, Ordering::Relaxed);
+				notify.notify_waiters();
+				true
+			}
+			_ => false,
+		});
+	}
+}
+
+/// Best-effort read of this process's resident set size, in bytes.
+///
+/// Returns `None` where no probe is implemented (e.g. wasm), in which case
+/// the memory-pressure sampler simply never trips.
+#[cfg(target_os = "linux")]
+fn current_rss() -> Option<usize> {
+	let status = std::fs::read_to_string("/proc/self/status").ok()?;
+	let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+	let kb: usize = line.split_whitespace().nth(1)?.parse().ok()?;
+	Some(kb * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn current_rss() -> Option<usize> {
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn wait_resolves_after_notify_races_the_check() {
+		let flag = Arc::new(AtomicBool::new(false));
+		let cancellation = Cancellation::new(None, vec![(flag.clone(), CancellationReason::Killed)]);
+
+		let waiter = tokio::spawn({
+			let cancellation = cancellation.clone();
+			async move { cancellation.wait().await }
+		});
+
+		// Let the spawned task reach `wait()` and register before we trip
+		// the flag, so this exercises the same register-then-check ordering
+		// `enable()` closes the race for, not just the deadline fallback.
+		tokio::task::yield_now().await;
+		flag.store(true, Ordering::Relaxed);
+		cancellation.notify();
+
+		let reason = tokio::time::timeout(Duration::from_secs(1), waiter)
+			.await
+			.expect("wait() should resolve promptly after notify(), not hang")
+			.unwrap();
+		assert_eq!(reason, CancellationReason::Killed);
+	}
+
+	#[test]
+	fn reason_prioritizes_deadline_over_flags() {
+		let flag = Arc::new(AtomicBool::new(true));
+		let cancellation = Cancellation::new(
+			Some(Instant::now() - Duration::from_millis(1)),
+			vec![(flag, CancellationReason::Killed)],
+		);
+		assert_eq!(cancellation.reason(), Some(CancellationReason::Timeout));
+	}
+
+	#[test]
+	fn reason_returns_the_first_tripped_flag() {
+		let untripped = Arc::new(AtomicBool::new(false));
+		let tripped = Arc::new(AtomicBool::new(true));
+		let cancellation = Cancellation::new(
+			None,
+			vec![
+				(untripped, CancellationReason::Killed),
+				(tripped, CancellationReason::ParentCancelled),
+			],
+		);
+		assert_eq!(cancellation.reason(), Some(CancellationReason::ParentCancelled));
+	}
+
+	#[test]
+	fn reason_is_none_when_nothing_tripped() {
+		let flag = Arc::new(AtomicBool::new(false));
+		let cancellation = Cancellation::new(None, vec![(flag, CancellationReason::Killed)]);
+		assert_eq!(cancellation.reason(), None);
+	}
+
+	#[test]
+	fn cancel_on_write_trips_stale_once_revision_advances() {
+		let revision = WriteRevision::new();
+		revision.bump();
+		revision.bump();
+		let cancellation = Cancellation::new(None, Vec::new()).cancel_on_write(&revision);
+		assert_eq!(cancellation.reason(), None);
+
+		revision.bump();
+		assert_eq!(cancellation.reason(), Some(CancellationReason::Stale));
+	}
+
+	#[test]
+	fn cancel_on_write_ignores_a_revision_at_the_snapshot() {
+		let revision = WriteRevision::new();
+		let cancellation = Cancellation::new(None, Vec::new()).cancel_on_write(&revision);
+
+		// No write has committed since the snapshot, so nothing should trip.
+		assert_eq!(cancellation.reason(), None);
+	}
+
+	#[tokio::test]
+	async fn write_revision_bump_wakes_a_waiting_context() {
+		let revision = WriteRevision::new();
+		let cancellation = Cancellation::new(None, Vec::new()).cancel_on_write(&revision);
+
+		let waiter = tokio::spawn({
+			let cancellation = cancellation.clone();
+			async move { cancellation.wait().await }
+		});
+
+		// Let the spawned task register in `wait()` before the write
+		// commits, so this exercises the wake path rather than a recheck
+		// that happened to already observe the new revision.
+		tokio::task::yield_now().await;
+		revision.bump();
+
+		let reason = tokio::time::timeout(Duration::from_secs(1), waiter)
+			.await
+			.expect("wait() should resolve promptly after bump(), not hang")
+			.unwrap();
+		assert_eq!(reason, CancellationReason::Stale);
+	}
+
+	#[test]
+	fn memory_pressure_trip_is_sticky_and_keeps_live_subscribers() {
+		let flag = Arc::new(AtomicBool::new(false));
+		let notify = Arc::new(tokio::sync::Notify::new());
+		let mut subscribers = vec![(Arc::downgrade(&flag), Arc::downgrade(&notify))];
+
+		MemoryPressureSampler::trip(&mut subscribers);
+		assert!(flag.load(Ordering::Relaxed));
+		assert_eq!(subscribers.len(), 1, "a live subscriber must not be pruned");
+
+		// A dip back below the ceiling only prunes, so it must not be able to
+		// clear a flag that has already tripped.
+		MemoryPressureSampler::prune(&mut subscribers);
+		assert!(flag.load(Ordering::Relaxed));
+	}
+
+	#[test]
+	fn memory_pressure_trip_prunes_dropped_subscribers() {
+		let flag = Arc::new(AtomicBool::new(false));
+		let notify = Arc::new(tokio::sync::Notify::new());
+		let mut subscribers = vec![(Arc::downgrade(&flag), Arc::downgrade(&notify))];
+		drop(flag);
+		drop(notify);
+
+		MemoryPressureSampler::trip(&mut subscribers);
+		assert!(subscribers.is_empty());
+	}
+
+	#[test]
+	fn memory_pressure_prune_drops_dead_entries_without_tripping_live_ones() {
+		let live_flag = Arc::new(AtomicBool::new(false));
+		let live_notify = Arc::new(tokio::sync::Notify::new());
+		let dead_flag = Arc::new(AtomicBool::new(false));
+		let dead_notify = Arc::new(tokio::sync::Notify::new());
+		let mut subscribers = vec![
+			(Arc::downgrade(&live_flag), Arc::downgrade(&live_notify)),
+			(Arc::downgrade(&dead_flag), Arc::downgrade(&dead_notify)),
+		];
+		drop(dead_flag);
+		drop(dead_notify);
+
+		// Below-ceiling samples call only `prune`, every tick, so a healthy
+		// server doesn't accumulate dead entries forever.
+		MemoryPressureSampler::prune(&mut subscribers);
+
+		assert_eq!(subscribers.len(), 1, "only the dropped subscriber should be pruned");
+		assert!(!live_flag.load(Ordering::Relaxed), "prune alone must not trip anything");
+	}
+
+	#[test]
+	fn should_yield_resets_after_the_budget_is_exhausted() {
+		let cancellation = Cancellation::new(None, Vec::new());
+		for _ in 0..DEFAULT_YIELD_BUDGET - 1 {
+			assert!(!cancellation.should_yield());
+		}
+		assert!(cancellation.should_yield());
+		// The counter should have reset rather than stay pinned at zero.
+		assert!(!cancellation.should_yield());
+	}
+
+	#[test]
+	fn default_starts_with_the_same_yield_budget_as_new() {
+		let cancellation = Cancellation::default();
+		for _ in 0..DEFAULT_YIELD_BUDGET - 1 {
+			assert!(!cancellation.should_yield());
+		}
+		assert!(cancellation.should_yield());
 	}
 }